@@ -30,11 +30,10 @@ fn scoped(b: &mut Bencher) {
     fn doit() -> uint {
         for _ in range(0, N) {
             FOO.with(|slot| {
-                let slot = slot.unwrap();
                 slot.set(slot.get() + 1);
             });
         }
-        FOO.with(|slot| slot.unwrap().get())
+        FOO.with(|slot| slot.get())
     }
 
     b.iter(|| {
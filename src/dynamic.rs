@@ -66,6 +66,11 @@ pub struct Key<T: 'static> {
     // initialization routine to invoke to create a value
     #[doc(hidden)]
     pub init: fn() -> T,
+    // shim to the thread-local flag tracking whether `init` is currently
+    // running on this thread, used to detect the initializer recursively
+    // accessing this same key.
+    #[doc(hidden)]
+    pub initializing: fn() -> Option<StaticRef<UnsafeCell<bool>>>,
 }
 
 /// A reference to a `Key`.
@@ -85,13 +90,18 @@ macro_rules! dynamic_tls(
     ($init:expr, $t:ty) => ({
         use std::cell::UnsafeCell as __UnsafeCell;
         tls!(static __KEY: __UnsafeCell<Option<$t>> = __UnsafeCell { value: None });
+        tls!(static __INITIALIZING: __UnsafeCell<bool> = __UnsafeCell { value: false });
         fn __init() -> $t { $init }
         fn __getit() -> Option<::tls::statik::Ref<__UnsafeCell<Option<$t>>>> {
             __KEY.get()
         }
+        fn __initializing() -> Option<::tls::statik::Ref<__UnsafeCell<bool>>> {
+            __INITIALIZING.get()
+        }
         ::tls::dynamic::Key {
             inner: __getit,
             init: __init,
+            initializing: __initializing,
         }
     });
 )
@@ -110,13 +120,102 @@ impl<T: 'static> Key<T> {
             Some(slot) => slot,
             None => return None,
         };
+        unsafe {
+            self.ensure_init(&slot);
+            Some(Ref::new((*slot.get()).as_ref().unwrap()))
+        }
+    }
+
+    /// Acquire a reference to the value in this TLS key, lazily initializing
+    /// it if necessary, and run `f` with that reference.
+    ///
+    /// Unlike `get`, this does not hand out a `Ref` whose lifetime the caller
+    /// must manage; the borrow is only valid for the duration of `f` and
+    /// cannot escape it or be sent to another thread.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the key currently has its destructor
+    /// running, or if the destructor has previously been run for this TLS
+    /// variable.
+    pub fn with<F, R>(&'static self, f: F) -> R where F: FnOnce(&T) -> R {
+        ::expect_live(self.try_with(f))
+    }
+
+    /// Acquire a reference to the value in this TLS key and run `f`, or
+    /// return `Err` if the slot is unavailable because its destructor is
+    /// running or has already run.
+    pub fn try_with<F, R>(&'static self, f: F) -> Result<R, ()>
+        where F: FnOnce(&T) -> R
+    {
+        let slot = match (self.inner)() {
+            Some(slot) => slot,
+            None => return Err(()),
+        };
+        unsafe {
+            self.ensure_init(&slot);
+            Ok(f((*slot.get()).as_ref().unwrap()))
+        }
+    }
+
+    /// Run the initializer if the slot is empty, guarding against the
+    /// initializer re-entrantly accessing this same key.
+    unsafe fn ensure_init(&'static self, slot: &StaticRef<UnsafeCell<Option<T>>>) {
+        if (*slot.get()).is_some() {
+            return;
+        }
+        let initializing = match (self.initializing)() {
+            Some(flag) => flag,
+            None => { *slot.get() = Some((self.init)()); return; }
+        };
+        if *initializing.get() {
+            panic!("dynamic TLS value's initializer recursively accessed itself");
+        }
+
+        // Clear the re-entrancy flag on the way out even if `init` unwinds,
+        // so a one-off panic in `init` doesn't leave every later access on
+        // this key spuriously panicking as "recursive" too.
+        struct Reset<'a> { flag: &'a UnsafeCell<bool> }
+        #[unsafe_destructor]
+        impl<'a> Drop for Reset<'a> {
+            fn drop(&mut self) {
+                unsafe { *self.flag.get() = false; }
+            }
+        }
+
+        *initializing.get() = true;
+        let _reset = Reset { flag: &*initializing };
+        let value = (self.init)();
+        *slot.get() = Some(value);
+    }
+
+    /// Query the state of this TLS key for the current thread.
+    ///
+    /// Unlike `get`, which initializes an empty slot on the spot and returns
+    /// a bare `None` once the slot is destroyed, this distinguishes "never
+    /// initialized", "live", and "destroyed" without running `init`, so
+    /// callers can decide whether it's safe to touch the key.
+    pub fn state(&'static self) -> ::LocalKeyState {
+        let slot = match (self.inner)() {
+            Some(slot) => slot,
+            None => return ::LocalKeyState::Destroyed,
+        };
         unsafe {
             if (*slot.get()).is_none() {
-                *slot.get() = Some((self.init)());
+                ::LocalKeyState::Uninitialized
+            } else {
+                ::LocalKeyState::Valid
             }
-            Some(Ref::new((*slot.get()).as_ref().unwrap()))
         }
     }
+
+    /// Cheaply check whether calling `with` right now would panic.
+    ///
+    /// Equivalent to `key.state() == LocalKeyState::Destroyed`, but reads
+    /// better at call sites that only care about the yes/no answer.
+    pub fn would_access_panic(&'static self) -> bool {
+        self.state() == ::LocalKeyState::Destroyed
+    }
 }
 
 impl<T: 'static> Ref<T> {
@@ -132,3 +231,41 @@ impl<T: 'static> Ref<T> {
 impl<T> Deref<T> for Ref<T> {
     fn deref<'a>(&'a self) -> &'a T { self.inner }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::rt::unwind;
+
+    #[test]
+    #[should_fail]
+    fn recursive_init_panics() {
+        dynamic_tls!(static FOO: uint = { FOO.with(|_| {}); 1 })
+
+        FOO.with(|_| {});
+    }
+
+    #[test]
+    fn panicking_init_does_not_stick() {
+        static mut CALLS: uint = 0;
+        dynamic_tls!(static FOO: uint = {
+            unsafe {
+                CALLS += 1;
+                if CALLS == 1 {
+                    panic!("first initializer call fails");
+                }
+                CALLS
+            }
+        })
+
+        // The first access's initializer panics; catch that on this same
+        // thread so the re-entrancy flag it sets is left to unwind through
+        // rather than simply never being reached.
+        assert!(unsafe { unwind::try(proc() { FOO.with(|_| {}); }) }.is_err());
+
+        // A second, non-recursive access on this same thread should re-run
+        // the initializer (which now succeeds) rather than spuriously
+        // panicking as "recursive" because the flag from the first,
+        // panicked attempt was left set.
+        FOO.with(|calls| assert_eq!(*calls, 2));
+    }
+}
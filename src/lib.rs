@@ -85,3 +85,31 @@ pub mod dynamic;
 mod tls {
     pub use {os, scoped, statik, dynamic};
 }
+
+/// Possible states a `statik::Key` or `dynamic::Key` may be in for the
+/// current thread.
+///
+/// These states are returned by the `state` method and allow a caller to
+/// distinguish a slot that has simply never been touched from one whose
+/// destructor is tearing it down, rather than collapsing both into `None`.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum LocalKeyState {
+    /// The key has not yet been initialized on this thread.
+    ///
+    /// Keys in this state will be lazily initialized on the next call to
+    /// `get` or `with`.
+    Uninitialized,
+    /// The key is holding a value for this thread and is safe to access.
+    Valid,
+    /// The key has had its destructor run and is not currently safe to
+    /// access; further accesses may return `None` or panic.
+    Destroyed,
+}
+
+/// Shared by `statik::Key::with` and `dynamic::Key::with`: unwraps the
+/// `Result` produced by their respective `try_with`, panicking in the cases
+/// both describe in their "# Panics" section.
+#[doc(hidden)]
+pub fn expect_live<R>(result: Result<R, ()>) -> R {
+    result.expect("cannot access a TLS value during or after it is destroyed")
+}
@@ -81,7 +81,7 @@ use std::sync::{Once, ONCE_INIT};
 pub struct StaticKey {
     /// Inner static TLS key (internals), created with by `INIT_INNER` in this
     /// module.
-    pub inner: StaticKeyInner,
+    pub inner: LazyKey,
     /// Destructor for the TLS value.
     ///
     /// See `Key::new` for information about when the destructor runs and how
@@ -89,8 +89,15 @@ pub struct StaticKey {
     pub dtor: Option<unsafe extern fn(*mut u8)>,
 }
 
-/// Inner contents of `StaticKey`, created by the `INIT_INNER` constant.
-pub struct StaticKeyInner {
+/// A TLS key whose only job is the one-time lazy allocation of the actual OS
+/// key.
+///
+/// `force` performs the same compare-and-swap dance that `StaticKey` used to
+/// inline directly, but caches the resolved key so that callers built on top
+/// of this module only pay for the atomic load once they've hoisted the
+/// result out of a loop, rather than on every single access.
+/// `StaticKey::get`/`set` themselves still call `force` on every access.
+pub struct LazyKey {
     key: AtomicUint,
     nc: marker::NoCopy,
 }
@@ -118,6 +125,7 @@ pub struct StaticKeyInner {
 /// ```
 pub struct Key {
     key: imp::Key,
+    dtor: Option<unsafe extern fn(*mut u8)>,
 }
 
 /// Constant initialization value for static TLS keys.
@@ -131,26 +139,102 @@ pub const INIT: StaticKey = StaticKey {
 /// Constant initialization value for the inner part of static TLS keys.
 ///
 /// This value allos specific configuration of the destructor for a TLS key.
-pub const INIT_INNER: StaticKeyInner = StaticKeyInner {
+pub const INIT_INNER: LazyKey = LazyKey {
     key: atomic::INIT_ATOMIC_UINT,
     nc: marker::NoCopy,
 };
 
+/// Re-export of the resolved, platform-specific OS TLS key type, named so
+/// code elsewhere in the crate can hold onto a `force`'d key without naming
+/// the (otherwise private) `imp::Key` type directly.
+#[doc(hidden)]
+pub use self::imp::Key as ResolvedKey;
+
 static INIT_KEYS: Once = ONCE_INIT;
-static mut KEYS: *mut Exclusive<Vec<imp::Key>> = 0 as *mut _;
+static mut KEYS: *mut Exclusive<Vec<(imp::Key, Option<unsafe extern fn(*mut u8)>)>> = 0 as *mut _;
+
+/// Gets the value behind an already-resolved OS TLS key.
+///
+/// This is a thin wrapper around the platform `get` primitive for callers
+/// that have already called `force` and cached the result, so that hot loops
+/// don't pay for the `StaticKey` atomic load on every access.
+pub unsafe fn get(key: ResolvedKey) -> *mut u8 { imp::get(key) }
+
+/// Sets the value behind an already-resolved OS TLS key.
+///
+/// See `get` above. This passes no destructor along, so it suits keys
+/// without one, which is the common case for callers hoisting the lookup out of a loop
+/// themselves. Use `set_with_dtor` for the destructor-bearing case.
+pub unsafe fn set(key: ResolvedKey, val: *mut u8) { imp::set(key, val, None) }
+
+/// Sets the value behind an already-resolved OS TLS key that carries a
+/// destructor.
+///
+/// Unlike `set`, this re-supplies `dtor` on every call, the same way
+/// `StaticKey::set` does internally: Windows needs it on each call to drive
+/// its per-thread destructor bookkeeping, while platforms that bind the
+/// destructor once at key-creation time via `pthread_key_create` simply
+/// ignore it here too.
+pub unsafe fn set_with_dtor(key: ResolvedKey, val: *mut u8,
+                            dtor: Option<unsafe extern fn(*mut u8)>) {
+    imp::set(key, val, dtor)
+}
+
+/// Registers `dtor` to be run on `t` when the current thread exits, with no
+/// OS TLS key involved at all.
+///
+/// This exists for targets (currently just Windows) where a native
+/// `#[thread_local]` static has no destructor hook of its own to lean on; it
+/// piggybacks on the same per-thread destructor list, and the same
+/// `.CRT$XLB` thread-detach callback, that the OS-key fallback below already
+/// maintains for exactly this reason.
+#[cfg(windows)]
+pub unsafe fn register_dtor(t: *mut u8, dtor: unsafe extern fn(*mut u8)) {
+    imp::register_dtor(t, dtor)
+}
+
+impl LazyKey {
+    /// Forces resolution of this key's OS TLS slot, lazily allocating it if
+    /// this is the first access.
+    ///
+    /// This performs the compare-and-swap "first one wins" dance exactly
+    /// once per key; once resolved, the returned key is cheap to hold onto
+    /// and use directly with the free `get`/`set` functions in this module,
+    /// avoiding a repeat atomic load.
+    pub unsafe fn force(&self, dtor: Option<unsafe extern fn(*mut u8)>) -> ResolvedKey {
+        match self.key.load(atomic::SeqCst) {
+            0 => self.lazy_init(dtor) as ResolvedKey,
+            n => n as ResolvedKey
+        }
+    }
+
+    unsafe fn lazy_init(&self, dtor: Option<unsafe extern fn(*mut u8)>) -> uint {
+        let key = imp::create(dtor);
+        assert!(key != 0);
+        match self.key.compare_and_swap(0, key as uint, atomic::SeqCst) {
+            // The CAS succeeded, so we've created the actual key
+            0 => {
+                register_key(key, dtor);
+                key as uint
+            }
+            // If someone beat us to the punch, use their key instead
+            n => { imp::destroy(key, dtor); n }
+        }
+    }
+}
 
 impl StaticKey {
     /// Gets the value associated with this TLS key
     ///
     /// This will lazily allocate a TLS key from the OS if one has not already
     /// been allocated.
-    pub unsafe fn get(&self) -> *mut u8 { imp::get(self.key()) }
+    pub unsafe fn get(&self) -> *mut u8 { imp::get(self.force()) }
 
     /// Sets this TLS key to a new value.
     ///
     /// This will lazily allocate a TLS key from the OS if one has not already
     /// been allocated.
-    pub unsafe fn set(&self, val: *mut u8) { imp::set(self.key(), val) }
+    pub unsafe fn set(&self, val: *mut u8) { imp::set(self.force(), val, self.dtor) }
 
     /// Deallocates this OS TLS key.
     ///
@@ -162,30 +246,17 @@ impl StaticKey {
     pub unsafe fn destroy(&self) {
         match self.inner.key.swap(0, atomic::SeqCst) {
             0 => {}
-            n => { unregister_key(n as imp::Key); imp::destroy(n as imp::Key) }
-        }
-    }
-
-    unsafe fn key(&self) -> imp::Key {
-        match self.inner.key.load(atomic::SeqCst) {
-            0 => self.lazy_init() as imp::Key,
-            n => n as imp::Key
-        }
-    }
-
-    unsafe fn lazy_init(&self) -> uint {
-        let key = imp::create(self.dtor);
-        assert!(key != 0);
-        match self.inner.key.compare_and_swap(0, key as uint, atomic::SeqCst) {
-            // The CAS succeeded, so we've created the actual key
-            0 => {
-                register_key(key);
-                key as uint
+            n => {
+                unregister_key(n as imp::Key);
+                imp::destroy(n as imp::Key, self.dtor)
             }
-            // If someone beat us to the punch, use their key instead
-            n => { imp::destroy(key); n }
         }
     }
+
+    /// Forces resolution of this key's OS TLS slot, for callers that want to
+    /// hoist the lookup out of a hot loop and call `get`/`set` in this
+    /// module directly afterwards.
+    pub unsafe fn force(&self) -> ResolvedKey { self.inner.force(self.dtor) }
 }
 
 impl Key {
@@ -201,7 +272,7 @@ impl Key {
     /// Note that the destructor will not be run when the `Key` goes out of
     /// scope.
     pub fn new(dtor: Option<unsafe extern fn(*mut u8)>) -> Key {
-        Key { key: unsafe { imp::create(dtor) } }
+        Key { key: unsafe { imp::create(dtor) }, dtor: dtor }
     }
 
     /// See StaticKey::get
@@ -211,42 +282,43 @@ impl Key {
 
     /// See StaticKey::set
     pub fn set(&self, val: *mut u8) {
-        unsafe { imp::set(self.key, val) }
+        unsafe { imp::set(self.key, val, self.dtor) }
     }
 }
 
 impl Drop for Key {
     fn drop(&mut self) {
-        unsafe { imp::destroy(self.key) }
+        unsafe { imp::destroy(self.key, self.dtor) }
     }
 }
 
 fn init_keys() {
-    let keys = box Exclusive::new(Vec::<imp::Key>::new());
+    let keys = box Exclusive::new(Vec::<(imp::Key, Option<unsafe extern fn(*mut u8)>)>::new());
     unsafe {
         KEYS = mem::transmute(keys);
     }
 
     rt::at_exit(proc() unsafe {
-        let keys: Box<Exclusive<Vec<imp::Key>>> = mem::transmute(KEYS);
+        let keys: Box<Exclusive<Vec<(imp::Key, Option<unsafe extern fn(*mut u8)>)>>> =
+            mem::transmute(KEYS);
         KEYS = 0 as *mut _;
         let keys = keys.lock();
-        for key in keys.iter() {
-            imp::destroy(*key);
+        for &(key, dtor) in keys.iter() {
+            imp::destroy(key, dtor);
         }
     });
 }
 
-fn register_key(key: imp::Key) {
+fn register_key(key: imp::Key, dtor: Option<unsafe extern fn(*mut u8)>) {
     INIT_KEYS.doit(init_keys);
     let mut keys = unsafe { (*KEYS).lock() };
-    keys.push(key);
+    keys.push((key, dtor));
 }
 
 fn unregister_key(key: imp::Key) {
     INIT_KEYS.doit(init_keys);
     let mut keys = unsafe { (*KEYS).lock() };
-    keys.retain(|k| *k != key);
+    keys.retain(|&(k, _)| k != key);
 }
 
 #[cfg(unix)]
@@ -261,7 +333,7 @@ mod imp {
         return key;
     }
 
-    pub unsafe fn set(key: Key, value: *mut u8) {
+    pub unsafe fn set(key: Key, value: *mut u8, _dtor: Option<unsafe extern fn(*mut u8)>) {
         let r = pthread_setspecific(key, value);
         debug_assert_eq!(r, 0);
     }
@@ -270,7 +342,7 @@ mod imp {
         pthread_getspecific(key)
     }
 
-    pub unsafe fn destroy(key: Key) {
+    pub unsafe fn destroy(key: Key, _dtor: Option<unsafe extern fn(*mut u8)>) {
         let r = pthread_key_delete(key);
         debug_assert_eq!(r, 0);
     }
@@ -294,9 +366,7 @@ mod imp {
 #[allow(dead_code)]
 mod imp {
     use std::mem;
-    use std::rt;
-    use std::rt::exclusive::Exclusive;
-    use std::sync::{ONCE_INIT, Once};
+    use std::sync::atomic::{AtomicBool, INIT_ATOMIC_BOOL, Relaxed};
     use libc::types::os::arch::extra::{DWORD, LPVOID, BOOL};
 
     pub type Key = DWORD;
@@ -317,18 +387,26 @@ mod imp {
     // To accomplish this feat, we perform a number of tasks, all contained
     // within this module:
     //
-    // * All TLS destructors are tracked by *us*, not the windows runtime. This
-    //   means that we have a global list of destructors for each TLS key that
-    //   we know about.
-    // * When a TLS key is destroyed, we're sure to remove it from the dtor list
-    //   if it's in there.
-    // * When a thread exits, we run over the entire list and run dtors for all
-    //   non-null keys. This attempts to match Unix semantics in this regard.
+    // * Destructors are tracked per-thread, not globally, in each thread's own
+    //   `#[thread_local]` list. Two kinds of entries can end up in it:
+    //   - `Keyed(key, dtor)`, pushed the moment a `Key` with a destructor is
+    //     first given a non-null value on this thread. The *value* isn't
+    //     stored: `set` can be called again later with a different pointer,
+    //     so at drain time we re-read whatever is currently live behind
+    //     `key` rather than trusting a stale snapshot.
+    //   - `Fixed(value, dtor)`, used by callers with no OS key at all (the
+    //     native `#[thread_local]` fast path in `statik`), where `value` is
+    //     simply run through `dtor` as-is.
+    // * A single process-wide `AtomicBool` records whether *any* destructor
+    //   has ever been registered on any thread, checked with `Relaxed`
+    //   ordering. This is purely an optimization so that threads which never
+    //   touch such a key can bail out of the exit hook at zero cost.
+    // * When a thread exits, we drain its own list, repeatedly, until it goes
+    //   dry (a destructor may itself register further destructors).
     //
-    // This ends up having the overhead of using a global list, having some
-    // locks here and there, and in general just adding some more code bloat. We
-    // attempt to optimize runtime by forgetting keys that don't have
-    // destructors, but this only gets us so far.
+    // Unlike the previous design this requires no global lock and no rescan of
+    // keys the exiting thread never touched: keys without destructors need no
+    // tracking at all.
     //
     // For more details and nitty-gritty, see the code sections below!
     //
@@ -336,8 +414,19 @@ mod imp {
     // [2]: https://github.com/ChromiumWebApps/chromium/blob/master/base
     //                        /threading/thread_local_storage_win.cc#L42
 
-    static INIT_DTORS: Once = ONCE_INIT;
-    static mut DTORS: *mut Exclusive<Vec<(Key, Dtor)>> = 0 as *mut _;
+    static HAS_DTORS: AtomicBool = INIT_ATOMIC_BOOL;
+
+    /// A pending per-thread destructor, see the module docs above.
+    enum Entry {
+        /// No backing OS key; just run `dtor` on the fixed `*mut u8`.
+        Fixed(*mut u8, Dtor),
+        /// Backed by a live OS key: the value may have changed since this was
+        /// registered, so it's re-read (and nulled) at drain time instead.
+        Keyed(Key, Dtor),
+    }
+
+    #[thread_local]
+    static mut DESTRUCTORS: *mut Vec<Entry> = 0 as *mut _;
 
     // -------------------------------------------------------------------------
     // Native bindings
@@ -345,18 +434,25 @@ mod imp {
     // This section is just raw bindings to the native functions that Windows
     // provides, There's a few extra calls to deal with destructors.
 
-    pub unsafe fn create(dtor: Option<Dtor>) -> Key {
+    pub unsafe fn create(_dtor: Option<Dtor>) -> Key {
         const TLS_OUT_OF_INDEXES: DWORD = 0xFFFFFFFF;
         let key = TlsAlloc();
         assert!(key != TLS_OUT_OF_INDEXES);
-        match dtor {
-            Some(f) => register_dtor(key, f),
-            None => {}
-        }
         return key;
     }
 
-    pub unsafe fn set(key: Key, value: *mut u8) {
+    pub unsafe fn set(key: Key, value: *mut u8, dtor: Option<Dtor>) {
+        match dtor {
+            // Only worth a `TlsGetValue` round-trip on keys that actually have
+            // a destructor; this is the moment `key` becomes non-null for the
+            // first time on this thread. We track `key` itself, not `value`,
+            // since a later `set` call may replace it with a different
+            // pointer that we still need to catch at drain time.
+            Some(dtor) if !value.is_null() && TlsGetValue(key).is_null() => {
+                register_key_dtor(key, dtor);
+            }
+            _ => {}
+        }
         let r = TlsSetValue(key, value as LPVOID);
         debug_assert!(r != 0);
     }
@@ -365,11 +461,11 @@ mod imp {
         TlsGetValue(key) as *mut u8
     }
 
-    pub unsafe fn destroy(key: Key) {
-        if unregister_dtor(key) {
+    pub unsafe fn destroy(key: Key, dtor: Option<Dtor>) {
+        match dtor {
             // FIXME: Currently if a key has a destructor associated with it we
-            //        can't actually ever unregister it. If we were to
-            //        unregister it, then any key destruction would have to be
+            //        can't actually ever free the OS TLS slot. If we were to
+            //        free it, then any key destruction would have to be
             //        serialized with respect to actually running destructors.
             //
             //        We want to avoid a race where right before run_dtors runs
@@ -381,9 +477,11 @@ mod imp {
             //        For now we just leak all keys with dtors to "fix" this.
             //        Note that source [2] above shows precedent for this sort
             //        of strategy.
-        } else {
-            let r = TlsFree(key)
-            debug_assert!(r != 0);
+            Some(..) => {}
+            None => {
+                let r = TlsFree(key);
+                debug_assert!(r != 0);
+            }
         }
     }
 
@@ -397,36 +495,28 @@ mod imp {
     // -------------------------------------------------------------------------
     // Dtor registration
     //
-    // These functions are associated with registering and unregistering
-    // destructors. They're pretty simple, they just push onto a vector and scan
-    // a vector currently.
-    //
-    // FIXME: This could probably be at least a little faster with a BTree.
+    // Unlike the old design, there's no key to scan and no lock to take: each
+    // thread just appends to its own list, lazily allocating it on first use.
 
-    fn init_dtors() {
-        let dtors = box Exclusive::new(Vec::<(Key, Dtor)>::new());
-        unsafe {
-            DTORS = mem::transmute(dtors);
+    unsafe fn destructors() -> &'static mut Vec<Entry> {
+        if DESTRUCTORS.is_null() {
+            let list: Box<Vec<Entry>> = box Vec::new();
+            DESTRUCTORS = mem::transmute(list);
         }
-
-        rt::at_exit(proc() unsafe {
-            mem::transmute::<_, Box<Exclusive<Vec<(Key, Dtor)>>>>(DTORS);
-            DTORS = 0 as *mut _;
-        });
+        &mut *DESTRUCTORS
     }
 
-    unsafe fn register_dtor(key: Key, dtor: Dtor) {
-        INIT_DTORS.doit(init_dtors);
-        let mut dtors = (*DTORS).lock();
-        dtors.push((key, dtor));
+    // `pub` so that callers outside this module (the native `#[thread_local]`
+    // fast path in `statik`, which has no OS key at all) can register a
+    // cleanup directly against this same per-thread list.
+    pub unsafe fn register_dtor(value: *mut u8, dtor: Dtor) {
+        HAS_DTORS.store(true, Relaxed);
+        destructors().push(Entry::Fixed(value, dtor));
     }
 
-    unsafe fn unregister_dtor(key: Key) -> bool {
-        if DTORS.is_null() { return false }
-        let mut dtors = (*DTORS).lock();
-        let before = dtors.len();
-        dtors.retain(|&(k, _)| k != key);
-        dtors.len() != before
+    unsafe fn register_key_dtor(key: Key, dtor: Dtor) {
+        HAS_DTORS.store(true, Relaxed);
+        destructors().push(Entry::Keyed(key, dtor));
     }
 
     // -------------------------------------------------------------------------
@@ -462,17 +552,10 @@ mod imp {
     //
     // # Ok, what's up with running all these destructors?
     //
-    // This will likely need to be improved over time, but this function
-    // attempts a "poor man's" destructor callback system. To do this we clone a
-    // local copy of the dtor list to start out with. This is our fudgy attempt
-    // to not hold the lock while destructors run and not worry about the list
-    // changing while we're looking at it.
-    //
-    // Once we've got a list of what to run, we iterate over all keys, check
-    // their values, and then run destructors if the values turn out to be non
-    // null (setting them to null just beforehand). We do this a few times in a
-    // loop to basically match Unix semantics. If we don't reach a fixed point
-    // after a short while then we just inevitably leak something most likely.
+    // Each thread owns its own destructor list, so there's no lock to take and
+    // no other thread's keys to consider. We drain the list in a loop, popping
+    // the whole thing out and running every entry, then checking whether any
+    // of those destructors pushed new entries back on before calling it done.
     //
     // # The article mentions crazy stuff about "/INCLUDE"?
     //
@@ -498,18 +581,27 @@ mod imp {
     }
 
     unsafe fn run_dtors() {
-        if DTORS.is_null() { return }
-        let mut any_run = true;
-        for _ in range(0, 5i) {
-            if !any_run { break }
-            any_run = false;
-            let dtors = (*DTORS).lock().iter().map(|p| *p).collect::<Vec<_>>();
-            for &(key, dtor) in dtors.iter() {
-                let ptr = TlsGetValue(key);
-                if !ptr.is_null() {
-                    TlsSetValue(key, 0 as *mut _);
-                    dtor(ptr as *mut _);
-                    any_run = true;
+        // Cheap early-out: no key anywhere has ever registered a destructor,
+        // so this thread can't possibly have any to run.
+        if !HAS_DTORS.load(Relaxed) { return }
+        if DESTRUCTORS.is_null() { return }
+
+        loop {
+            let pending = mem::replace(&mut *DESTRUCTORS, Vec::new());
+            if pending.is_empty() { break }
+            for entry in pending.into_iter() {
+                match entry {
+                    Entry::Fixed(value, dtor) => dtor(value),
+                    Entry::Keyed(key, dtor) => {
+                        // Re-read the live value rather than a stale
+                        // snapshot, and reset the slot to null ourselves
+                        // before invoking the destructor, matching
+                        // `Key::new`'s documented contract.
+                        let value = TlsGetValue(key) as *mut u8;
+                        if value.is_null() { continue }
+                        TlsSetValue(key, 0 as LPVOID);
+                        dtor(value);
+                    }
                 }
             }
         }
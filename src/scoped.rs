@@ -19,15 +19,13 @@
 //! scoped_tls!(static FOO: uint)
 //!
 //! // Initially each scoped TLS slot is empty.
-//! FOO.with(|slot| {
-//!     assert_eq!(slot, None);
-//! });
+//! assert!(!FOO.is_set());
 //!
 //! // When inserting a value into TLS, the value is only in place for the
 //! // duration of the closure specified.
 //! FOO.set(&1, || {
 //!     FOO.with(|slot| {
-//!         assert_eq!(slot.map(|x| *x), Some(1));
+//!         assert_eq!(*slot, 1);
 //!     });
 //! });
 //! # }
@@ -41,10 +39,17 @@ pub use self::imp::KeyInner;
 /// parameter `T`.
 ///
 /// Keys are statically allocated and can contain a reference to an instance of
-/// type `T` scoped to a particular lifetime. Keys provides two methods, `set`
-/// and `with`, both of which currently use closures to control the scope of
-/// their contents.
-pub struct Key<T> { #[doc(hidden)] pub inner: KeyInner<T> }
+/// type `T` scoped to a particular lifetime. Keys provide `set`/`with` (and
+/// their `_mut` and `try_with` counterparts), all of which use closures to
+/// control the scope of their contents.
+///
+/// `T` may be unsized (a trait object, `[T]`, or `str`) when the
+/// `#[thread_local]`-backed implementation is in use, since the slot then
+/// stores the full (possibly fat) pointer directly. The OS-key fallback used
+/// when `#[thread_local]` isn't available can only carry a thin pointer, so
+/// it remains `Sized`-only; instantiating it with an unsized `T` simply
+/// fails to compile.
+pub struct Key<T: ?Sized> { #[doc(hidden)] pub inner: KeyInner<T> }
 
 /// Declare a new scoped TLS key.
 ///
@@ -57,7 +62,7 @@ macro_rules! scoped_tls(
     );
 );
 
-impl<T> Key<T> {
+impl<T: ?Sized> Key<T> {
     /// Insert a value into this scoped TLS slot for a duration of a closure.
     ///
     /// While `cb` is running, the value `t` will be returned by `get` unless
@@ -75,7 +80,7 @@ impl<T> Key<T> {
     /// scoped_tls!(static FOO: uint)
     ///
     /// FOO.set(&100, || {
-    ///     let val = FOO.with(|v| *v.unwrap());
+    ///     let val = FOO.with(|v| *v);
     ///     assert_eq!(val, 100);
     ///
     ///     // set can be called recursively
@@ -84,18 +89,18 @@ impl<T> Key<T> {
     ///     });
     ///
     ///     // Recursive calls restore the previous value.
-    ///     let val = FOO.with(|v| *v.unwrap());
+    ///     let val = FOO.with(|v| *v);
     ///     assert_eq!(val, 100);
     /// });
     /// # }
     /// ```
     pub fn set<R>(&'static self, t: &T, cb: || -> R) -> R {
-        struct Reset<'a, T: 'a> {
+        struct Reset<'a, T: 'a + ?Sized> {
             key: &'a KeyInner<T>,
-            val: *mut T,
+            val: Option<*mut T>,
         }
         #[unsafe_destructor]
-        impl<'a, T> Drop for Reset<'a, T> {
+        impl<'a, T: ?Sized> Drop for Reset<'a, T> {
             fn drop(&mut self) {
                 unsafe { self.key.set(self.val) }
             }
@@ -103,7 +108,7 @@ impl<T> Key<T> {
 
         let prev = unsafe {
             let prev = self.inner.get();
-            self.inner.set(t as *const T as *mut T);
+            self.inner.set(Some(t as *const T as *mut T));
             prev
         };
 
@@ -111,6 +116,37 @@ impl<T> Key<T> {
         cb()
     }
 
+    /// Get a value out of this scoped TLS variable.
+    ///
+    /// This function takes a closure which receives a reference to the value
+    /// of this TLS variable.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `set` has not yet been called on this
+    /// variable in the current scope. Use `try_with` to get an `Option`
+    /// instead of panicking.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #![feature(phase)]
+    /// # #[phase(plugin, link)] extern crate tls;
+    /// # fn main() {
+    /// scoped_tls!(static FOO: uint)
+    ///
+    /// FOO.set(&1, || {
+    ///     FOO.with(|val| {
+    ///         assert_eq!(*val, 1);
+    ///     });
+    /// });
+    /// # }
+    /// ```
+    pub fn with<R>(&'static self, cb: |&T| -> R) -> R {
+        self.try_with(|slot| cb(slot.expect("cannot access a scoped thread local \
+                                              variable without calling `set` first")))
+    }
+
     /// Get a value out of this scoped TLS variable.
     ///
     /// This function takes a closure which receives the value of this TLS
@@ -125,32 +161,139 @@ impl<T> Key<T> {
     /// # fn main() {
     /// scoped_tls!(static FOO: uint)
     ///
-    /// FOO.with(|slot| {
+    /// FOO.try_with(|slot| {
     ///     // work with `slot`
     /// });
     /// # }
     /// ```
-    pub fn with<R>(&'static self, cb: |Option<&T>| -> R) -> R {
+    pub fn try_with<R>(&'static self, cb: |Option<&T>| -> R) -> R {
         unsafe {
+            match self.inner.get() {
+                Some(ptr) => cb(Some(&*ptr)),
+                None => cb(None),
+            }
+        }
+    }
+
+    /// Test whether this TLS variable has currently been set via `set`.
+    pub fn is_set(&'static self) -> bool {
+        unsafe { self.inner.get().is_some() }
+    }
+
+    /// Insert a mutable reference into this scoped TLS slot for the duration
+    /// of a closure.
+    ///
+    /// This works just like `set`, except that the closure passed to
+    /// `with_mut` is handed `&mut T` instead of `&T`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #![feature(phase)]
+    /// # #[phase(plugin, link)] extern crate tls;
+    /// # fn main() {
+    /// scoped_tls!(static FOO: uint)
+    ///
+    /// let mut val = 100u;
+    /// FOO.set_mut(&mut val, || {
+    ///     FOO.with_mut(|v| *v.unwrap() += 1);
+    /// });
+    /// assert_eq!(val, 101);
+    /// # }
+    /// ```
+    pub fn set_mut<R>(&'static self, t: &mut T, cb: || -> R) -> R {
+        struct Reset<'a, T: 'a + ?Sized> {
+            key: &'a KeyInner<T>,
+            val: Option<*mut T>,
+        }
+        #[unsafe_destructor]
+        impl<'a, T: ?Sized> Drop for Reset<'a, T> {
+            fn drop(&mut self) {
+                unsafe { self.key.set(self.val) }
+            }
+        }
+
+        let prev = unsafe {
+            let prev = self.inner.get();
+            self.inner.set(Some(t as *mut T));
+            prev
+        };
+
+        let _reset = Reset { key: &self.inner, val: prev };
+        cb()
+    }
+
+    /// Get a mutable reference out of this scoped TLS variable.
+    ///
+    /// This function takes a closure which receives `&mut T` if this variable
+    /// is currently set via `set_mut`, or `None` otherwise.
+    ///
+    /// To guarantee that two live `&mut T` can never coexist, the pointer is
+    /// moved out of the slot (and replaced with null) for the duration of
+    /// `cb`, exactly like the `Reset` guard used by `set`/`set_mut` restores
+    /// the previous value on the way out. This means a nested call to
+    /// `try_with`/`with_mut` for the same key sees `None` (and a nested
+    /// `with` panics, as it does for any unset slot) rather than a second
+    /// alias of the same reference; this mirrors `try_with`'s convention of
+    /// yielding `None` for unavailable slots rather than panicking.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #![feature(phase)]
+    /// # #[phase(plugin, link)] extern crate tls;
+    /// # fn main() {
+    /// scoped_tls!(static FOO: uint)
+    ///
+    /// FOO.with_mut(|slot| {
+    ///     assert_eq!(slot, None);
+    /// });
+    /// # }
+    /// ```
+    pub fn with_mut<R>(&'static self, cb: |Option<&mut T>| -> R) -> R {
+        struct Reset<'a, T: 'a + ?Sized> {
+            key: &'a KeyInner<T>,
+            val: Option<*mut T>,
+        }
+        #[unsafe_destructor]
+        impl<'a, T: ?Sized> Drop for Reset<'a, T> {
+            fn drop(&mut self) {
+                unsafe { self.key.set(self.val) }
+            }
+        }
+
+        let ptr = unsafe {
             let ptr = self.inner.get();
-            if ptr.is_null() {
-                cb(None)
-            } else {
-                cb(Some(&*ptr))
+            self.inner.set(None);
+            ptr
+        };
+
+        let _reset = Reset { key: &self.inner, val: ptr };
+        unsafe {
+            match ptr {
+                Some(ptr) => cb(Some(&mut *ptr)),
+                None => cb(None),
             }
         }
     }
 }
 
-#[cfg(feature = "thread-local")]
+#[cfg(target_thread_local)]
 #[macro_escape]
 mod imp {
 
     use std::cell::UnsafeCell;
 
     // TODO: Should be a `Cell`, but that's not `Sync`
+    //
+    // The slot holds `Option<*mut T>` rather than a null-sentineled `*mut T`
+    // so that it can be const-initialized to `None` even when `T` is
+    // unsized (a trait object, `[T]`, or `str`): `None` carries no
+    // `T`-shaped payload, whereas there's no way to conjure up a null *fat*
+    // pointer generically. This is what lets `scoped_tls!` back an unsized
+    // `T` here exactly like a sized one.
     #[doc(hidden)]
-    pub struct KeyInner<T> { pub inner: UnsafeCell<*mut T> }
+    pub struct KeyInner<T: ?Sized> { pub inner: UnsafeCell<Option<*mut T>> }
 
     #[macro_export]
     macro_rules! scoped_tls_inner(
@@ -158,26 +301,31 @@ mod imp {
             #[thread_local]
             static $name: ::tls::scoped::Key<$t> = ::tls::scoped::Key {
                 inner: ::tls::scoped::KeyInner {
-                    inner: ::std::cell::UnsafeCell { value: 0 as *mut _ },
+                    inner: ::std::cell::UnsafeCell { value: None },
                 }
             };
         );
     );
 
-    impl<T> KeyInner<T> {
+    impl<T: ?Sized> KeyInner<T> {
         #[doc(hidden)]
-        pub unsafe fn set(&self, ptr: *mut T) { *self.inner.get() = ptr; }
+        pub unsafe fn set(&self, ptr: Option<*mut T>) { *self.inner.get() = ptr; }
         #[doc(hidden)]
-        pub unsafe fn get(&self) -> *mut T { *self.inner.get() }
+        pub unsafe fn get(&self) -> Option<*mut T> { *self.inner.get() }
     }
 }
 
-#[cfg(not(feature = "thread-local"))]
+#[cfg(not(target_thread_local))]
 #[macro_escape]
 mod imp {
     use std::kinds::marker;
     use os::StaticKey as OsStaticKey;
 
+    // Unlike the `#[thread_local]` backend above, this fallback only has a
+    // single OS-TLS slot's worth of thin pointer to work with, so it can't
+    // round-trip a fat pointer and stays `Sized`-only; instantiating
+    // `scoped_tls!` for an unsized `T` under this cfg fails to compile here
+    // rather than silently truncating the pointer's metadata.
     #[doc(hidden)]
     pub struct KeyInner<T> {
         pub inner: OsStaticKey,
@@ -198,9 +346,17 @@ mod imp {
 
     impl<T> KeyInner<T> {
         #[doc(hidden)]
-        pub unsafe fn set(&self, ptr: *mut T) { self.inner.set(ptr as *mut _) }
+        pub unsafe fn set(&self, ptr: Option<*mut T>) {
+            match ptr {
+                Some(ptr) => self.inner.set(ptr as *mut _),
+                None => self.inner.set(0 as *mut _),
+            }
+        }
         #[doc(hidden)]
-        pub unsafe fn get(&self) -> *mut T { self.inner.get() as *mut _ }
+        pub unsafe fn get(&self) -> Option<*mut T> {
+            let ptr = self.inner.get() as *mut T;
+            if ptr.is_null() { None } else { Some(ptr) }
+        }
     }
 }
 
@@ -213,27 +369,103 @@ mod tests {
     fn smoke() {
         scoped_tls!(static BAR: uint);
 
-        BAR.with(|slot| {
+        assert!(!BAR.is_set());
+        BAR.try_with(|slot| {
             assert_eq!(slot, None);
         });
         BAR.set(&1, || {
+            assert!(BAR.is_set());
             BAR.with(|slot| {
+                assert_eq!(*slot, 1);
+            });
+            BAR.try_with(|slot| {
                 assert_eq!(slot.map(|x| *x), Some(1));
             });
         });
-        BAR.with(|slot| {
+        assert!(!BAR.is_set());
+        BAR.try_with(|slot| {
             assert_eq!(slot, None);
         });
     }
 
+    #[test]
+    #[should_fail]
+    fn with_panics_when_unset() {
+        scoped_tls!(static BAR: uint);
+
+        BAR.with(|_| {});
+    }
+
     #[test]
     fn cell_allowed() {
         scoped_tls!(static BAR: Cell<uint>);
 
         BAR.set(&Cell::new(1), || {
-            BAR.with(|slot| {
+            BAR.try_with(|slot| {
                 assert_eq!(slot.map(|x| x.get()), Some(1));
             });
         });
     }
+
+    #[test]
+    fn smoke_mut() {
+        scoped_tls!(static BAR: uint);
+
+        BAR.with_mut(|slot| {
+            assert!(slot.is_none());
+        });
+        let mut val = 1u;
+        BAR.set_mut(&mut val, || {
+            BAR.with_mut(|slot| {
+                *slot.unwrap() += 1;
+            });
+        });
+        assert_eq!(val, 2);
+        BAR.with_mut(|slot| {
+            assert!(slot.is_none());
+        });
+    }
+
+    #[test]
+    fn unsized_trait_object() {
+        trait Greet { fn greet(&self) -> uint; }
+        struct Loud(uint);
+        impl Greet for Loud { fn greet(&self) -> uint { self.0 } }
+
+        scoped_tls!(static BAR: Greet);
+
+        let loud = Loud(7);
+        BAR.set(&loud, || {
+            BAR.with(|g| {
+                assert_eq!(g.greet(), 7);
+            });
+        });
+        assert!(!BAR.is_set());
+    }
+
+    #[test]
+    fn unsized_slice() {
+        scoped_tls!(static BAR: [uint]);
+
+        let v = [1u, 2, 3];
+        BAR.set(&v, || {
+            BAR.with(|slice| {
+                assert_eq!(slice, [1u, 2, 3].as_slice());
+            });
+        });
+    }
+
+    #[test]
+    fn with_mut_nested_sees_none() {
+        scoped_tls!(static BAR: uint);
+
+        let mut val = 1u;
+        BAR.set_mut(&mut val, || {
+            BAR.with_mut(|_outer| {
+                BAR.with_mut(|inner| {
+                    assert!(inner.is_none());
+                });
+            });
+        });
+    }
 }
@@ -130,6 +130,53 @@ impl<T: 'static> Key<T> {
     pub fn get(&'static self) -> Option<Ref<T>> {
         self.inner.get()
     }
+
+    /// Query the state of this TLS key for the current thread.
+    ///
+    /// This lets code that runs during thread teardown (destructors of other
+    /// TLS keys, for example) decide whether it's safe to touch this key
+    /// rather than guessing from a bare `None` returned by `get`.
+    pub fn state(&'static self) -> ::LocalKeyState {
+        self.inner.state()
+    }
+
+    /// Cheaply check whether calling `with` right now would panic.
+    ///
+    /// This is equivalent to `key.state() == LocalKeyState::Destroyed` but
+    /// reads better at call sites that only care about the yes/no answer,
+    /// such as destructors of other TLS keys deciding whether it's safe to
+    /// touch this one.
+    pub fn would_access_panic(&'static self) -> bool {
+        self.state() == ::LocalKeyState::Destroyed
+    }
+
+    /// Acquire a reference to the value in this TLS key and run `f`.
+    ///
+    /// This is the RFC-461-style closure accessor: the borrow handed to `f`
+    /// is scoped strictly to the call and cannot escape it, which avoids the
+    /// `UnsafeCell`/raw-pointer juggling `get` otherwise requires for
+    /// `RefCell`-like payloads.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the key currently has its destructor
+    /// running, or if the destructor has previously been run for this TLS
+    /// variable.
+    pub fn with<F, R>(&'static self, f: F) -> R where F: FnOnce(&T) -> R {
+        ::expect_live(self.try_with(f))
+    }
+
+    /// Acquire a reference to the value in this TLS key and run `f`, or
+    /// return `Err` if the slot is unavailable because its destructor is
+    /// running or has already run.
+    pub fn try_with<F, R>(&'static self, f: F) -> Result<R, ()>
+        where F: FnOnce(&T) -> R
+    {
+        match self.get() {
+            Some(r) => Ok(f(&*r)),
+            None => Err(()),
+        }
+    }
 }
 
 impl<T> Ref<T> {
@@ -146,7 +193,14 @@ impl<T> Deref<T> for Ref<T> {
     fn deref<'a>(&'a self) -> &'a T { self.inner }
 }
 
-#[cfg(feature = "thread-local")]
+// The native `#[thread_local]` storage below is both faster than routing
+// through an OS TLS key (no `pthread_getspecific`/`TlsGetValue` indirection)
+// and selected automatically: whenever the target supports LLVM's
+// `#[thread_local]` attribute, `tls!`/`dynamic_tls!` get it for free with no
+// change to their public API. Targets without that support (and, for now,
+// destructor plumbing) fall through to the OS-key implementation further
+// down.
+#[cfg(target_thread_local)]
 mod imp {
     #![macro_escape]
 
@@ -215,6 +269,19 @@ mod imp {
                           destroy_value::<T>);
             *self.dtor_registered.get() = true;
         }
+
+        pub fn state(&'static self) -> ::LocalKeyState {
+            unsafe {
+                if intrinsics::needs_drop::<T>() && *self.dtor_running.get() {
+                    ::LocalKeyState::Destroyed
+                } else if intrinsics::needs_drop::<T>() &&
+                          !*self.dtor_registered.get() {
+                    ::LocalKeyState::Uninitialized
+                } else {
+                    ::LocalKeyState::Valid
+                }
+            }
+        }
     }
 
     // Since what appears to be glibc 2.18 this symbol has been shipped which
@@ -245,35 +312,64 @@ mod imp {
             return
         }
 
-        // The fallback implementation uses a vanilla OS-based TLS key to track
-        // the list of destructors that need to be run for this thread. The key
-        // then has its own destructor which runs all the other destructors.
+        // The fallback implementation keeps the list of destructors that need
+        // to run for this thread directly in a `#[thread_local]` static
+        // rather than routing every registration through an extra OS-TLS
+        // indirection. A `#[thread_local]` state machine tracks whether
+        // we've armed the one OS key whose only job is noticing this thread
+        // is exiting:
         //
-        // The destructor for DTORS is a little special in that it has a `while`
-        // loop to continuously drain the list of registered destructors. It
-        // *should* be the case that this loop always terminates because we
-        // provide the guarantee that a TLS key cannot be set after it is
-        // flagged for destruction.
-        static DTORS: os::StaticKey = os::StaticKey {
-            inner: os::INIT_INNER,
-            dtor: Some(run_dtors),
-        };
+        // * `Unregistered` -> no destructors registered yet on this thread.
+        // * `Registered`   -> the exit signal is armed; more destructors may
+        //                     still be pushed onto the list.
+        // * `Running`      -> the list is currently being drained; the exit
+        //                     signal must not be re-armed (it already fired),
+        //                     but further destructors discovered mid-drain
+        //                     (self-referential or cyclic ones) still get
+        //                     pushed and are picked up by the next iteration
+        //                     of the drain loop below.
         type List = Vec<(*mut u8, unsafe extern fn(*mut u8))>;
-        if DTORS.get().is_null() {
-            let v: Box<List> = box Vec::new();
-            DTORS.set(mem::transmute(v));
+
+        #[deriving(PartialEq)]
+        enum DtorState { Unregistered, Registered, Running }
+
+        #[thread_local]
+        static mut STATE: DtorState = DtorState::Unregistered;
+        #[thread_local]
+        static DTORS: UnsafeCell<*mut List> = UnsafeCell { value: 0 as *mut _ };
+
+        if STATE == DtorState::Unregistered {
+            // Arm exactly one OS key for this thread. Its stored value is
+            // just a non-null sentinel; all the real work happens in its
+            // destructor, `signal_exit`.
+            static EXIT_SIGNAL: os::StaticKey = os::StaticKey {
+                inner: os::INIT_INNER,
+                dtor: Some(signal_exit),
+            };
+            EXIT_SIGNAL.set(1 as *mut u8);
+            STATE = DtorState::Registered;
         }
-        let list: &mut List = &mut *(DTORS.get() as *mut List);
-        list.push((t, dtor));
 
-        unsafe extern fn run_dtors(mut ptr: *mut u8) {
-            while !ptr.is_null() {
+        if (*DTORS.get()).is_null() {
+            let list: Box<List> = box Vec::new();
+            *DTORS.get() = mem::transmute(list);
+        }
+        (**DTORS.get()).push((t, dtor));
+
+        unsafe extern fn signal_exit(_: *mut u8) {
+            STATE = DtorState::Running;
+            // Popping the whole list out and running it, then checking
+            // whether anything re-populated it, lets destructors that
+            // themselves register further destructors (see the
+            // `dtors_in_dtors_in_dtors` test) still get a chance to run.
+            loop {
+                let ptr = *DTORS.get();
+                if ptr.is_null() { break }
+                *DTORS.get() = 0 as *mut _;
                 let list: Box<List> = mem::transmute(ptr);
                 for &(ptr, dtor) in list.iter() {
                     dtor(ptr);
                 }
-                ptr = DTORS.get();
-                DTORS.set(0 as *mut _);
             }
         }
     }
@@ -290,6 +386,16 @@ mod imp {
         _tlv_atexit(dtor, t);
     }
 
+    // Windows has no value-carrying destructor hook for either OS TLS or a
+    // `#[thread_local]` static, so we register directly with the same
+    // per-thread destructor list and `.CRT$XLB` thread-detach callback that
+    // `os`'s OS-key fallback already maintains for exactly this reason.
+    #[cfg(windows)]
+    unsafe fn register_dtor(t: *mut u8, dtor: unsafe extern fn(*mut u8)) {
+        use os;
+        os::register_dtor(t, dtor);
+    }
+
     #[doc(hidden)]
     pub unsafe extern fn destroy_value<T>(ptr: *mut u8) {
         let ptr = ptr as *mut Key<T>;
@@ -301,20 +407,40 @@ mod imp {
     }
 }
 
-#[cfg(not(feature = "thread-local"))]
+// `os::create`/`imp::create` already threads a destructor straight through
+// to `pthread_key_create` (see `OsStaticKey::dtor` below, set to `__destroy`
+// by the `tls!` macro) -- it did even before this fallback existed, so
+// there's no missing `os::create_with_dtor` entry point to add here. What a
+// single native OS destructor can't give this fallback for free is the
+// `Uninitialized`/`Destroyed` distinction `state()` exposes: POSIX resets a
+// key's value to null before invoking its destructor, and again before any
+// later teardown round, so that same slot can't also carry a sticky
+// "already destroyed" marker. The `destroyed` key below exists to provide
+// exactly that, and is unrelated to destructor dispatch itself.
+#[cfg(not(target_thread_local))]
 mod imp {
     #![macro_escape]
 
     use std::mem;
 
+    use os;
     use super::Ref;
     use os::StaticKey as OsStaticKey;
 
     #[doc(hidden)]
     pub struct Key<T> {
         pub inner: T,
+        // Carries the boxed value; its destructor (installed via the `tls!`
+        // macro) drives teardown.
         pub os: OsStaticKey,
-        pub valid: OsStaticKey,
+        // A second, destructor-less OS key used purely so `state()` can keep
+        // reporting `Destroyed` for the rest of the thread's life. `os`
+        // itself gets reset to null by `destroy_value`, and the OS resets it
+        // to null again before invoking the destructor on any later
+        // teardown round, so it can't double as a sticky marker. A key with
+        // no destructor is never touched by that dance, so it simply holds
+        // whatever we last wrote to it.
+        pub destroyed: OsStaticKey,
     }
 
     struct Value<T: 'static> {
@@ -338,7 +464,7 @@ mod imp {
                         inner: ::tls::os::INIT_INNER,
                         dtor: Some(__destroy),
                     },
-                    valid: ::tls::os::INIT,
+                    destroyed: ::tls::os::INIT,
                 },
             }
         });
@@ -352,12 +478,29 @@ mod imp {
             }
         }
 
+        pub fn state(&'static self) -> ::LocalKeyState {
+            unsafe {
+                if !self.destroyed.get().is_null() {
+                    ::LocalKeyState::Destroyed
+                } else if self.os.get().is_null() {
+                    ::LocalKeyState::Uninitialized
+                } else {
+                    ::LocalKeyState::Valid
+                }
+            }
+        }
+
         unsafe fn ptr(&'static self) -> Option<*mut T> {
-            let ptr = self.os.get() as *mut Value<T>;
+            if !self.destroyed.get().is_null() {
+                return None
+            }
+
+            // Resolve the OS key once and reuse it for both the lookup and,
+            // if necessary, the store below, rather than letting `get`/`set`
+            // each independently re-force it.
+            let key = self.os.force();
+            let ptr = os::get(key) as *mut Value<T>;
             if !ptr.is_null() {
-                if ptr as uint == 1 {
-                    return None
-                }
                 return Some(&mut (*ptr).value as *mut T);
             }
 
@@ -372,25 +515,25 @@ mod imp {
                 value: mem::transmute_copy(&self.inner),
             };
             let ptr: *mut Value<T> = mem::transmute(ptr);
-            self.os.set(ptr as *mut u8);
+            os::set_with_dtor(key, ptr as *mut u8, self.os.dtor);
             Some(&mut (*ptr).value as *mut T)
         }
     }
 
     #[doc(hidden)]
     pub unsafe extern fn destroy_value<T: 'static>(ptr: *mut u8) {
-        // The OS TLS ensures that this key contains a NULL value when this
-        // destructor starts to run. We set it back to a sentinel value of 1 to
-        // ensure that any future calls to `get` for this thread will return
-        // `None`.
-        //
-        // Note that to prevent an infinite loop we reset it back to null right
-        // before we return from the destructor ourselves.
+        // The OS TLS ensures that `os` contains a NULL value when this
+        // destructor starts to run (and will reset it to NULL again before
+        // invoking us on any later teardown round on this same key), so `os`
+        // can't be used to remember that we've already torn down. Flip the
+        // separate `destroyed` key instead, which the OS never touches on
+        // its own, before running the real destructor so any TLS access
+        // from within `T`'s own drop (or a sibling destructor later in this
+        // same teardown) sees `Destroyed` rather than resurrecting the key.
         let ptr: Box<Value<T>> = mem::transmute(ptr);
         let key = ptr.key;
-        key.os.set(1 as *mut u8);
+        key.destroyed.set(1 as *mut u8);
         drop(ptr);
-        key.os.set(0 as *mut u8);
     }
 }
 
@@ -498,6 +641,36 @@ mod tests {
         }).join();
     }
 
+    #[test]
+    fn state_destroyed_is_sticky() {
+        struct S1;
+        struct S2;
+        tls!(static K1: UnsafeCell<Option<S1>> = UnsafeCell { value: None })
+        tls!(static K2: UnsafeCell<Option<S2>> = UnsafeCell { value: None })
+
+        impl Drop for S1 {
+            fn drop(&mut self) {
+                // Register K2 here, after K1 has already begun tearing
+                // down, so its destructor is guaranteed to observe K1 only
+                // after K1 itself has been fully destroyed.
+                unsafe { *K2.get().unwrap().get() = Some(S2); }
+            }
+        }
+        impl Drop for S2 {
+            fn drop(&mut self) {
+                assert_eq!(K1.state(), ::LocalKeyState::Destroyed);
+                assert!(K1.would_access_panic());
+                assert!(K1.get().is_none());
+            }
+        }
+
+        Thread::start(proc() unsafe {
+            assert_eq!(K1.state(), ::LocalKeyState::Uninitialized);
+            *K1.get().unwrap().get() = Some(S1);
+            assert_eq!(K1.state(), ::LocalKeyState::Valid);
+        }).join();
+    }
+
     #[test]
     fn dtors_in_dtors_in_dtors() {
         struct S1(Sender<()>);